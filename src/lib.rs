@@ -1,7 +1,5 @@
 mod utils;
 
-use core::panic;
-
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::Clamped;
 use web_sys::ImageData;
@@ -35,7 +33,7 @@ pub struct QoiHeader {
     colorspace: u8, // 0 = sRGB with linear alpha, 1 = all channels linear
 }
 
-#[derive(Debug, Default, Clone, Copy)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 struct QoiColor {
     r: u8,
     g: u8,
@@ -66,6 +64,33 @@ const QOI_PIXELS_MAX: u32 = 400000000;
 const QOI_HEADER_SIZE: usize = 14;
 const QOI_END_SEGMENT_SIZE: usize = 8;
 
+#[derive(Debug)]
+pub enum QoiError {
+    InvalidMagic { got: u32 },
+    EmptyImage,
+    BadChannels(u8),
+    BadColorspace(u8),
+    ImageTooLarge,
+    UnexpectedEof { offset: usize },
+    TrailingGarbage,
+    OutputBufferTooSmall { required: usize },
+}
+
+impl From<QoiError> for JsValue {
+    fn from(err: QoiError) -> Self {
+        JsValue::from_str(&match err {
+            QoiError::InvalidMagic { got } => format!("Not a valid QOI image: bad magic {got:#010x}"),
+            QoiError::EmptyImage => "Not a valid QOI image: zero width or height".to_string(),
+            QoiError::BadChannels(c) => format!("Not a valid QOI image: unsupported channel count {c}"),
+            QoiError::BadColorspace(c) => format!("Not a valid QOI image: unsupported colorspace {c}"),
+            QoiError::ImageTooLarge => "Not a valid QOI image: dimensions exceed maximum".to_string(),
+            QoiError::UnexpectedEof { offset } => format!("Unexpected end of QOI stream at offset {offset}"),
+            QoiError::TrailingGarbage => "Corrupt QOI byte stream".to_string(),
+            QoiError::OutputBufferTooSmall { required } => format!("Output buffer too small: need {required} bytes"),
+        })
+    }
+}
+
 enum QoiOp {
     Index(usize),
     Diff(QoiColor),
@@ -73,7 +98,6 @@ enum QoiOp {
     Run(usize),
     Rgb,
     Rgba,
-    Unknown,
 }
 
 impl From<u8> for QoiOp {
@@ -93,14 +117,15 @@ impl From<u8> for QoiOp {
             })
         } else if (b & 0xc0) == 0x80 {
             QoiOp::Luma((b & 0x3f) - 32)
-        } else if (b & 0xc0) == 0xc0 {
-            QoiOp::Run((b & 0x3f) as usize)
         } else {
-            QoiOp::Unknown
+            // The only remaining two-bit tag is 0xc0.
+            QoiOp::Run((b & 0x3f) as usize)
         }
     }
 }
 
+const QOI_END_MARKER: [u8; QOI_END_SEGMENT_SIZE] = [0, 0, 0, 0, 0, 0, 0, 1];
+
 fn read_32(bytes: &[u8], offset: &mut usize) -> u32 {
     let a = bytes[*offset] as u32;
     let b = bytes[*offset + 1] as u32;
@@ -116,6 +141,13 @@ fn read_8(bytes: &[u8], offset: &mut usize) -> u8 {
     a
 }
 
+fn write_32(bytes: &mut Vec<u8>, value: u32) {
+    bytes.push(((value & 0xff000000) >> 24) as u8);
+    bytes.push(((value & 0x00ff0000) >> 16) as u8);
+    bytes.push(((value & 0x0000ff00) >> 8) as u8);
+    bytes.push((value & 0x000000ff) as u8);
+}
+
 #[derive(Default, Debug, Clone)]
 #[wasm_bindgen]
 pub struct QoiImage {
@@ -144,14 +176,279 @@ impl QoiImage {
     pub fn get_bytes(&self) -> Vec<u8> {
         self.bytes.clone()
     }
+
+    pub fn encode(&self, run2: bool) -> Vec<u8> {
+        encode_qoi(
+            &self.bytes,
+            self.header.width,
+            self.header.height,
+            self.header.channels,
+            self.header.colorspace,
+            run2,
+        )
+    }
+}
+
+/// Flush an outstanding run. With `run2` enabled on three-channel images a run
+/// longer than the 62-pixel `QOI_OP_RUN` cap is written as the extended op: the
+/// `0xff` tag followed by a big-endian `run - 1`.
+fn emit_run(bytes: &mut Vec<u8>, run: usize, run2: bool, channels: u8) {
+    if run2 && channels == 3 && run > 62 {
+        bytes.push(0xff);
+        bytes.push(((run - 1) >> 8) as u8);
+        bytes.push(((run - 1) & 0xff) as u8);
+    } else {
+        bytes.push(0xc0 | (run - 1) as u8);
+    }
 }
 
 #[wasm_bindgen]
-pub fn decode_qoi(bytes: &[u8]) -> Result<ImageData, JsValue> {
+pub fn encode_qoi(
+    pixels: &[u8],
+    width: u32,
+    height: u32,
+    channels: u8,
+    colorspace: u8,
+    run2: bool,
+) -> Vec<u8> {
     utils::set_panic_hook();
+    let px_len = (width * height) as usize * channels as usize;
+
+    let mut bytes: Vec<u8> = Vec::with_capacity(QOI_HEADER_SIZE + px_len / 2 + QOI_END_SEGMENT_SIZE);
+    write_32(&mut bytes, QOI_MAGIC);
+    write_32(&mut bytes, width);
+    write_32(&mut bytes, height);
+    bytes.push(channels);
+    bytes.push(colorspace);
+
+    let mut prev_color = QoiColor::from(0x000000FF);
+    let mut seen_colors: [QoiColor; 64] = [QoiColor::from(0x00000000); 64];
+    let mut run: usize = 0;
+    let run_cap = if run2 && channels == 3 { 65536 } else { 62 };
+
+    for px_pos in (0..px_len).step_by(channels as usize) {
+        let color = QoiColor {
+            r: pixels[px_pos],
+            g: pixels[px_pos + 1],
+            b: pixels[px_pos + 2],
+            a: if channels == 4 { pixels[px_pos + 3] } else { prev_color.a },
+        };
+
+        if color == prev_color {
+            run += 1;
+            if run == run_cap || px_pos + channels as usize >= px_len {
+                emit_run(&mut bytes, run, run2, channels);
+                run = 0;
+            }
+        } else {
+            if run > 0 {
+                emit_run(&mut bytes, run, run2, channels);
+                run = 0;
+            }
+
+            let hash = color.hash() % 64;
+            if seen_colors[hash] == color {
+                bytes.push(hash as u8);
+            } else {
+                seen_colors[hash] = color;
+
+                if color.a == prev_color.a {
+                    let dr = color.r.wrapping_sub(prev_color.r) as i8;
+                    let dg = color.g.wrapping_sub(prev_color.g) as i8;
+                    let db = color.b.wrapping_sub(prev_color.b) as i8;
+                    let dr_dg = dr.wrapping_sub(dg);
+                    let db_dg = db.wrapping_sub(dg);
+
+                    if (-2..=1).contains(&dr) && (-2..=1).contains(&dg) && (-2..=1).contains(&db) {
+                        bytes.push(
+                            0x40 | (((dr + 2) as u8) << 4)
+                                | (((dg + 2) as u8) << 2)
+                                | ((db + 2) as u8),
+                        );
+                    } else if (-32..=31).contains(&dg)
+                        && (-8..=7).contains(&dr_dg)
+                        && (-8..=7).contains(&db_dg)
+                    {
+                        bytes.push(0x80 | (dg + 32) as u8);
+                        bytes.push((((dr_dg + 8) as u8) << 4) | (db_dg + 8) as u8);
+                    } else {
+                        bytes.push(0xfe);
+                        bytes.push(color.r);
+                        bytes.push(color.g);
+                        bytes.push(color.b);
+                    }
+                } else {
+                    bytes.push(0xff);
+                    bytes.push(color.r);
+                    bytes.push(color.g);
+                    bytes.push(color.b);
+                    bytes.push(color.a);
+                }
+            }
+
+            prev_color = color;
+        }
+    }
+
+    bytes.extend_from_slice(&QOI_END_MARKER);
+    bytes
+}
+
+#[derive(Debug, Clone)]
+#[wasm_bindgen]
+pub struct QoiStreamDecoder {
+    header: QoiHeader,
+    pixels: Vec<u8>,
+    prev_color: QoiColor,
+    seen_colors: [QoiColor; 64],
+    run: usize,
+    cur_pixel: usize,
+    painted_rows: u32,
+    buf: Vec<u8>,
+    run2: bool,
+}
+
+#[wasm_bindgen]
+impl QoiStreamDecoder {
+    #[wasm_bindgen(constructor)]
+    pub fn new(header_bytes: &[u8], run2: bool) -> Result<QoiStreamDecoder, JsValue> {
+        utils::set_panic_hook();
+        let header = parse_header(header_bytes)?;
+
+        let px_len = (header.width * header.height * 4) as usize;
+        Ok(QoiStreamDecoder {
+            header,
+            pixels: vec![0; px_len],
+            prev_color: QoiColor::from(0x000000FF),
+            seen_colors: [QoiColor::from(0x00000000); 64],
+            run: 0,
+            cur_pixel: 0,
+            painted_rows: 0,
+            buf: Vec::new(),
+            run2,
+        })
+    }
+
+    pub fn feed(&mut self, chunk: &[u8]) {
+        self.buf.extend_from_slice(chunk);
+    }
+
+    pub fn decode_rows(&mut self, max_rows: u32) -> Result<Option<ImageData>, JsValue> {
+        let width = self.header.width;
+        let target = ((self.painted_rows + max_rows).min(self.header.height) * width) as usize;
+
+        let mut idx = 0_usize;
+        while self.cur_pixel < target {
+            if self.run > 0 {
+                self.run -= 1;
+            } else {
+                if idx >= self.buf.len() {
+                    break;
+                }
+                let b1 = self.buf[idx];
+
+                // In 3-channel `run2` streams the RGBA tag is an extended run
+                // whose two-byte length may straddle a chunk boundary.
+                if self.run2 && self.header.channels == 3 && b1 == 0xff {
+                    if idx + 1 + 2 > self.buf.len() {
+                        break;
+                    }
+                    idx += 1;
+                    let hi = read_8(&self.buf, &mut idx) as usize;
+                    let lo = read_8(&self.buf, &mut idx) as usize;
+                    self.run = (hi << 8) | lo;
+
+                    let px_pos = self.cur_pixel * 4;
+                    self.pixels[px_pos] = self.prev_color.r;
+                    self.pixels[px_pos + 1] = self.prev_color.g;
+                    self.pixels[px_pos + 2] = self.prev_color.b;
+                    self.pixels[px_pos + 3] = 255;
+                    self.cur_pixel += 1;
+                    continue;
+                }
+
+                // An op can carry trailing bytes; if the chunk boundary lands in
+                // the middle of one, leave it buffered until `feed` brings more.
+                let extra = match QoiOp::from(b1) {
+                    QoiOp::Rgb => 3,
+                    QoiOp::Rgba => 4,
+                    QoiOp::Luma(_) => 1,
+                    _ => 0,
+                };
+                if idx + 1 + extra > self.buf.len() {
+                    break;
+                }
+                idx += 1;
+
+                match QoiOp::from(b1) {
+                    QoiOp::Rgb => {
+                        self.prev_color.r = read_8(&self.buf, &mut idx);
+                        self.prev_color.g = read_8(&self.buf, &mut idx);
+                        self.prev_color.b = read_8(&self.buf, &mut idx);
+                    }
+                    QoiOp::Rgba => {
+                        self.prev_color.r = read_8(&self.buf, &mut idx);
+                        self.prev_color.g = read_8(&self.buf, &mut idx);
+                        self.prev_color.b = read_8(&self.buf, &mut idx);
+                        self.prev_color.a = read_8(&self.buf, &mut idx);
+                    }
+                    QoiOp::Index(i) => {
+                        self.prev_color = self.seen_colors[i];
+                    }
+                    QoiOp::Diff(c) => {
+                        self.prev_color.r += c.r;
+                        self.prev_color.g += c.g;
+                        self.prev_color.b += c.b
+                    }
+                    QoiOp::Luma(vg) => {
+                        let b2 = read_8(&self.buf, &mut idx);
+                        self.prev_color.r += vg - 8 + ((b2 >> 4) & 0x0f);
+                        self.prev_color.g += vg;
+                        self.prev_color.b += vg - 8 + (b2 & 0x0f);
+                    }
+                    QoiOp::Run(runs) => {
+                        self.run = runs;
+                    }
+                }
+
+                self.seen_colors[self.prev_color.hash() % 64] = self.prev_color;
+            }
+
+            let px_pos = self.cur_pixel * 4;
+            self.pixels[px_pos] = self.prev_color.r;
+            self.pixels[px_pos + 1] = self.prev_color.g;
+            self.pixels[px_pos + 2] = self.prev_color.b;
+            self.pixels[px_pos + 3] = if self.header.channels == 4 {
+                self.prev_color.a
+            } else {
+                255
+            };
+            self.cur_pixel += 1;
+        }
+
+        self.buf.drain(0..idx);
+
+        // Only hand back the scanlines that are fully decoded since last call.
+        // A chunk that doesn't complete a row (or a call after the image is
+        // finished) yields nothing new rather than a zero-height `ImageData`,
+        // which the browser constructor rejects with `IndexSizeError`.
+        let ready_rows = (self.cur_pixel / width as usize) as u32 - self.painted_rows;
+        if ready_rows == 0 {
+            return Ok(None);
+        }
+        let start = (self.painted_rows * width * 4) as usize;
+        let end = start + (ready_rows * width * 4) as usize;
+        self.painted_rows += ready_rows;
+
+        ImageData::new_with_u8_clamped_array_and_sh(Clamped(&self.pixels[start..end]), width, ready_rows)
+            .map(Some)
+    }
+}
+
+fn parse_header(bytes: &[u8]) -> Result<QoiHeader, JsValue> {
     let size = bytes.len();
     if size < QOI_HEADER_SIZE {
-        panic!("File too small to be a valid QOI image");
+        return Err(QoiError::UnexpectedEof { offset: size }.into());
     }
 
     let mut index = 0_usize;
@@ -163,31 +460,87 @@ pub fn decode_qoi(bytes: &[u8]) -> Result<ImageData, JsValue> {
         colorspace: read_8(bytes, &mut index),
     };
 
-    if header.width == 0
-        || header.height == 0
-        || header.channels < 3
-        || header.channels > 4
-        || header.colorspace > 1
-        || header.magic != QOI_MAGIC
-        || header.height >= QOI_PIXELS_MAX / header.width
-    {
-        panic!("Not a valid QOI image");
+    if header.magic != QOI_MAGIC {
+        return Err(QoiError::InvalidMagic { got: header.magic }.into());
+    }
+    if header.width == 0 || header.height == 0 {
+        return Err(QoiError::EmptyImage.into());
+    }
+    if header.channels < 3 || header.channels > 4 {
+        return Err(QoiError::BadChannels(header.channels).into());
+    }
+    if header.colorspace > 1 {
+        return Err(QoiError::BadColorspace(header.colorspace).into());
+    }
+    if header.height >= QOI_PIXELS_MAX / header.width {
+        return Err(QoiError::ImageTooLarge.into());
     }
 
-    // Can only construct ImageData from RGBA
-    let px_len: usize = (header.width * header.height * 4) as usize;
-    let mut pixels: Vec<u8> = vec![0; px_len];
+    Ok(header)
+}
 
+/// Decode into a caller-owned buffer laid out as `channels` bytes per pixel,
+/// avoiding the full-image allocation that `decode_qoi` performs. `out` must
+/// hold at least `width * height * channels` bytes; three-channel output is
+/// packed RGB while four-channel output carries alpha (255 for RGB sources).
+#[wasm_bindgen]
+pub fn decode_to_buf(bytes: &[u8], out: &mut [u8], channels: u8, run2: bool) -> Result<(), JsValue> {
+    utils::set_panic_hook();
+    let header = parse_header(bytes)?;
+
+    let stride = channels as usize;
+    let required = (header.width * header.height) as usize * stride;
+    if out.len() < required {
+        return Err(QoiError::OutputBufferTooSmall { required }.into());
+    }
+
+    let size = bytes.len();
+    let mut index = QOI_HEADER_SIZE;
     let mut prev_color = QoiColor::from(0x000000FF);
-    let mut seen_colors: [QoiColor; 64] = [QoiColor::from(0x000000FF); 64];
+    let mut seen_colors: [QoiColor; 64] = [QoiColor::from(0x00000000); 64];
     let mut run: usize = 0;
     let chunks_len: usize = size - QOI_END_SEGMENT_SIZE;
-    for px_pos in (0..px_len).step_by(4) {
+    for px_pos in (0..required).step_by(stride) {
         if run > 0 {
             run -= 1;
         } else if index < chunks_len {
             let b1 = read_8(bytes, &mut index);
 
+            // In 3-channel `run2` streams the otherwise-unused RGBA tag is reinterpreted
+            // as an extended run carrying a big-endian length in the next two bytes.
+            if run2 && header.channels == 3 && b1 == 0xff {
+                if index + 2 > chunks_len {
+                    return Err(QoiError::UnexpectedEof { offset: index }.into());
+                }
+                let hi = read_8(bytes, &mut index) as usize;
+                let lo = read_8(bytes, &mut index) as usize;
+                run = (hi << 8) | lo;
+
+                out[px_pos] = prev_color.r;
+                out[px_pos + 1] = prev_color.g;
+                out[px_pos + 2] = prev_color.b;
+                if stride == 4 {
+                    out[px_pos + 3] = if header.channels == 4 {
+                        prev_color.a
+                    } else {
+                        255
+                    };
+                }
+                continue;
+            }
+
+            // A multi-byte op whose tail spills past the chunk region means the
+            // stream was truncated mid-op.
+            let extra = match QoiOp::from(b1) {
+                QoiOp::Rgb => 3,
+                QoiOp::Rgba => 4,
+                QoiOp::Luma(_) => 1,
+                _ => 0,
+            };
+            if index + extra > chunks_len {
+                return Err(QoiError::UnexpectedEof { offset: index }.into());
+            }
+
             match QoiOp::from(b1) {
                 QoiOp::Rgb => {
                     prev_color.r = read_8(bytes, &mut index);
@@ -217,23 +570,44 @@ pub fn decode_qoi(bytes: &[u8]) -> Result<ImageData, JsValue> {
                 QoiOp::Run(runs) => {
                     run = runs;
                 }
-                QoiOp::Unknown => {
-                    unreachable!("Not a valid QoiOp.")
-                }
             }
 
             seen_colors[prev_color.hash() % 64] = prev_color;
+        } else {
+            // Pixels remain but the ops ran out: the stream is truncated.
+            return Err(QoiError::UnexpectedEof { offset: index }.into());
         }
 
-        pixels[px_pos] = prev_color.r;
-        pixels[px_pos + 1] = prev_color.g;
-        pixels[px_pos + 2] = prev_color.b;
-        pixels[px_pos + 3] = if header.channels == 4 {
-            prev_color.a
-        } else {
-            255
+        out[px_pos] = prev_color.r;
+        out[px_pos + 1] = prev_color.g;
+        out[px_pos + 2] = prev_color.b;
+        if stride == 4 {
+            out[px_pos + 3] = if header.channels == 4 {
+                prev_color.a
+            } else {
+                255
+            };
         }
     }
 
+    // Every pixel is decoded: whatever is left must be exactly the 8-byte end
+    // marker. Leftover op bytes or a mangled marker mean a corrupt stream.
+    if index != chunks_len || bytes[chunks_len..] != QOI_END_MARKER {
+        return Err(QoiError::TrailingGarbage.into());
+    }
+
+    Ok(())
+}
+
+#[wasm_bindgen]
+pub fn decode_qoi(bytes: &[u8], run2: bool) -> Result<ImageData, JsValue> {
+    utils::set_panic_hook();
+    let header = parse_header(bytes)?;
+
+    // Can only construct ImageData from RGBA
+    let px_len: usize = (header.width * header.height * 4) as usize;
+    let mut pixels: Vec<u8> = vec![0; px_len];
+    decode_to_buf(bytes, &mut pixels, 4, run2)?;
+
     ImageData::new_with_u8_clamped_array_and_sh(Clamped(&pixels), header.width, header.height)
 }